@@ -1,81 +1,959 @@
 //! This module contains the logic for filtering files based on include and exclude patterns.
 
 use colored::*;
-use glob::Pattern;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
 use log::{debug, error};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use regex::Regex;
 
-/// Determines whether a file should be included based on include and exclude patterns.
+/// Walks up from `start_dir` toward the filesystem root, collecting every
+/// `.gitignore` file along the way, and stops once a `.git` directory is
+/// found (the repository root). Each line becomes a `PatternRule`, reusing
+/// the same negation and last-match-wins machinery `CompiledPatterns`
+/// already provides for CLI patterns, instead of a second ad-hoc matcher.
+///
+/// A directory-style rule (e.g. `target` or `/build/`) needs two glob
+/// patterns to behave like git does: one that matches the path itself, and
+/// one that matches anything nested under it (`<pattern>/**`).
+/// Non-anchored rules (no interior or leading `/`) get `**/` spliced in so
+/// they match at any depth under the directory that declared them, the way
+/// a bare gitignore name like `target` applies anywhere in the subtree.
 ///
 /// # Arguments
 ///
-/// * `path` - The path to the file to be checked.
-/// * `include_patterns` - A slice of strings representing the include patterns.
-/// * `exclude_patterns` - A slice of strings representing the exclude patterns.
-/// * `include_priority` - A boolean indicating whether to give priority to include patterns if both include and exclude patterns match.
+/// * `start_dir` - The directory to start searching from, typically the
+///   parent directory of the file being checked.
 ///
 /// # Returns
 ///
-/// * `bool` - `true` if the file should be included, `false` otherwise.
-pub fn should_include_file(
-    path: &Path,
-    include_patterns: &[String],
-    exclude_patterns: &[String],
-    include_priority: bool,
-) -> bool {
-    // ~~~ Clean path ~~~
-    let canonical_path = match fs::canonicalize(path) {
-        Ok(path) => path,
-        Err(e) => {
-            error!("Failed to canonicalize path: {}", e);
-            return false;
+/// * `Vec<PatternRule>` - All collected rules, in the order they were
+///   declared (closest-to-root first).
+fn collect_gitignore_patterns(start_dir: &Path) -> Vec<PatternRule> {
+    let mut patterns = Vec::new();
+    let mut dirs = Vec::new();
+
+    let mut current = Some(start_dir);
+    while let Some(dir) = current {
+        dirs.push(dir.to_path_buf());
+        if dir.join(".git").is_dir() {
+            break;
         }
-    };
-    let path_str = canonical_path.to_str().unwrap_or("");
-    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-
-    // ~~~ Check patterns ~~~
-    let included = include_patterns.iter().any(|pattern| {
-        // Try as glob pattern first
-        if let Ok(glob) = Pattern::new(pattern) {
-            if glob.matches(path_str) {
-                return true;
+        current = dir.parent();
+    }
+
+    // Read them root-first so later (closer) .gitignore files are evaluated
+    // after the ones higher up the tree, matching git's own precedence.
+    for dir in dirs.into_iter().rev() {
+        let gitignore_path = dir.join(".gitignore");
+        let Ok(contents) = fs::read_to_string(&gitignore_path) else {
+            continue;
+        };
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, rest) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let anchored = rest.trim_end_matches('/').contains('/');
+            let name = rest.trim_start_matches('/').trim_end_matches('/');
+            let prefix = if anchored {
+                format!("{}/", dir.to_string_lossy())
+            } else {
+                format!("{}/**/", dir.to_string_lossy())
+            };
+
+            for pattern in [format!("{}{}", prefix, name), format!("{}{}/**", prefix, name)] {
+                patterns.push(PatternRule {
+                    pattern,
+                    syntax: PatternSyntax::Glob,
+                    negated,
+                    scope: None,
+                });
             }
         }
+    }
+
+    patterns
+}
+
+/// The syntax a pattern is written in, mirroring Mercurial's pattern
+/// language. Selected with a prefix on the raw pattern string; `glob:` is
+/// the default when no prefix is given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternSyntax {
+    /// A shell glob, e.g. `*.rs` or `src/**/*.toml`.
+    Glob,
+    /// A regular expression tested with `Regex::is_match`, so it can match
+    /// any part of the path unless it is anchored with `^`/`$`.
+    Regex,
+    /// An exact directory, matched recursively (`<dir>` and everything under it).
+    Path,
+    /// Files directly inside `<dir>`, but not in its subdirectories.
+    RootFilesIn,
+}
+
+/// A single filter pattern together with its syntax and negation flag. A
+/// pattern prefixed with `!` (the gitignore convention) re-includes a path
+/// that an earlier, non-negated pattern excluded.
+#[derive(Debug, Clone)]
+pub struct PatternRule {
+    pub pattern: String,
+    pub syntax: PatternSyntax,
+    pub negated: bool,
+    /// Set when this rule was pulled in via `subinclude:`, restricting it to
+    /// paths under this directory. `None` for a rule written inline or
+    /// pulled in via a plain `include:`.
+    pub scope: Option<PathBuf>,
+}
+
+impl PatternRule {
+    /// Parses a raw CLI pattern, stripping a leading `!` into the `negated`
+    /// flag and a `glob:`/`re:`/`regex:`/`path:`/`rootfilesin:` prefix into
+    /// `syntax`.
+    pub fn parse(raw: &str) -> Self {
+        let (negated, rest) = match raw.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, raw),
+        };
+
+        let (syntax, pattern) = if let Some(rest) = rest.strip_prefix("glob:") {
+            (PatternSyntax::Glob, rest)
+        } else if let Some(rest) = rest
+            .strip_prefix("re:")
+            .or_else(|| rest.strip_prefix("regex:"))
+        {
+            (PatternSyntax::Regex, rest)
+        } else if let Some(rest) = rest.strip_prefix("rootfilesin:") {
+            (PatternSyntax::RootFilesIn, rest)
+        } else if let Some(rest) = rest.strip_prefix("path:") {
+            (PatternSyntax::Path, rest)
+        } else {
+            (PatternSyntax::Glob, rest)
+        };
 
-        // Try as simple wildcard pattern
-        if let Some(wildcard_pattern) = pattern.strip_suffix('*') {
-            if file_name.starts_with(wildcard_pattern) {
-                return true;
+        PatternRule {
+            pattern: pattern.to_string(),
+            syntax,
+            negated,
+            scope: None,
+        }
+    }
+
+    /// Convenience helper for building a rule list from CLI flag values,
+    /// expanding any `include:`/`subinclude:` directives along the way.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PatternError` if an included file can't be read or an
+    /// `include:`/`subinclude:` chain cycles back on itself.
+    pub fn parse_all(raw: &[String]) -> Result<Vec<PatternRule>, PatternError> {
+        let mut seen = HashSet::new();
+        let mut expanded = Vec::new();
+        for line in raw {
+            expand_pattern(line, None, None, &mut seen, &mut expanded)?;
+        }
+        Ok(expanded)
+    }
+
+    /// Renders this rule's pattern as a glob string, for syntaxes that can
+    /// be expressed as one (everything except `Regex`).
+    fn as_glob_pattern(&self) -> String {
+        let dir = self.pattern.trim_end_matches('/');
+        match self.syntax {
+            PatternSyntax::Glob => self.pattern.clone(),
+            PatternSyntax::Path => format!("{}/**", dir),
+            PatternSyntax::RootFilesIn => format!("{}/*", dir),
+            PatternSyntax::Regex => unreachable!("regex patterns are compiled separately"),
+        }
+    }
+}
+
+/// Resolves an `include:`/`subinclude:` target relative to the directory of
+/// the file that referenced it, or relative to the working directory for a
+/// top-level directive.
+fn resolve_include_path(anchor: Option<&Path>, file: &str) -> PathBuf {
+    let candidate = Path::new(file);
+    match anchor {
+        Some(base) if candidate.is_relative() => base.join(candidate),
+        _ => candidate.to_path_buf(),
+    }
+}
+
+/// Anchors a pattern pulled in via `subinclude:` to the directory containing
+/// the file that declared it, so it only applies under that subtree.
+/// `Glob`, `Path`, and `RootFilesIn` patterns splice `base` directly into
+/// their directory string; a `regex:` pattern has no such convention to
+/// splice into, so it's instead given a `scope` that's checked separately
+/// at match time.
+fn anchor_rule(rule: PatternRule, base: &Path) -> PatternRule {
+    match rule.syntax {
+        PatternSyntax::Glob => {
+            let anchored = format!(
+                "{}/**/{}",
+                base.to_string_lossy(),
+                rule.pattern.trim_start_matches('/')
+            );
+            PatternRule {
+                pattern: anchored,
+                ..rule
             }
         }
+        PatternSyntax::Path | PatternSyntax::RootFilesIn => {
+            let anchored = format!(
+                "{}/{}",
+                base.to_string_lossy(),
+                rule.pattern.trim_start_matches('/')
+            );
+            PatternRule {
+                pattern: anchored,
+                ..rule
+            }
+        }
+        PatternSyntax::Regex => PatternRule {
+            scope: Some(base.to_path_buf()),
+            ..rule
+        },
+    }
+}
+
+/// Expands a single raw pattern line, recursively splicing in the contents
+/// of `include:`/`subinclude:` targets.
+///
+/// * `resolve_dir` - directory of the file currently being expanded, used
+///   only to resolve a relative `include:`/`subinclude:` target to an
+///   absolute path. Always kept up to date as expansion descends, for both
+///   directive kinds, so a nested relative include inside an already-included
+///   file still resolves against the right directory.
+/// * `scope` - the directory the *patterns themselves* are restricted to,
+///   set only once expansion has descended into a `subinclude:` and
+///   propagated to everything nested under it (including further plain
+///   `include:`s), since those patterns are still subject to the enclosing
+///   `subinclude:`'s scope.
+fn expand_pattern(
+    raw: &str,
+    resolve_dir: Option<&Path>,
+    scope: Option<&Path>,
+    seen: &mut HashSet<PathBuf>,
+    out: &mut Vec<PatternRule>,
+) -> Result<(), PatternError> {
+    let rest = raw.strip_prefix('!').unwrap_or(raw);
+
+    if let Some(file) = rest.strip_prefix("subinclude:") {
+        return expand_include_file(file, resolve_dir, scope, true, seen, out);
+    }
+    if let Some(file) = rest.strip_prefix("include:") {
+        return expand_include_file(file, resolve_dir, scope, false, seen, out);
+    }
 
-        // Try exact match
-        file_name == pattern
+    let rule = PatternRule::parse(raw);
+    out.push(match scope {
+        Some(base) => anchor_rule(rule, base),
+        None => rule,
     });
+    Ok(())
+}
+
+/// Reads `file` (resolved against `resolve_dir`), guards against include
+/// cycles, and expands every line it contains. When `scoped` is set (a
+/// `subinclude:`), contributed patterns are anchored to the included file's
+/// directory; a plain `include:` splices its patterns in as if written
+/// inline at the call site, inheriting whatever `scope` is already active.
+fn expand_include_file(
+    file: &str,
+    resolve_dir: Option<&Path>,
+    scope: Option<&Path>,
+    scoped: bool,
+    seen: &mut HashSet<PathBuf>,
+    out: &mut Vec<PatternRule>,
+) -> Result<(), PatternError> {
+    let path = resolve_include_path(resolve_dir, file);
+    let key = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+
+    if !seen.insert(key.clone()) {
+        return Err(PatternError::Cycle { path: key });
+    }
+
+    let contents = fs::read_to_string(&path).map_err(|source| PatternError::Io {
+        path: path.clone(),
+        source,
+    })?;
+    let next_resolve_dir = path.parent().map(Path::to_path_buf);
+    let next_scope = if scoped {
+        next_resolve_dir.clone()
+    } else {
+        scope.map(Path::to_path_buf)
+    };
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        expand_pattern(
+            line,
+            next_resolve_dir.as_deref(),
+            next_scope.as_deref(),
+            seen,
+            out,
+        )?;
+    }
+
+    seen.remove(&key);
+    Ok(())
+}
+
+/// Running match state for a path as patterns are evaluated in order, used
+/// to implement gitignore-style "last match wins" semantics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchState {
+    /// No pattern has matched yet.
+    None,
+    /// The most recent matching pattern excluded this path.
+    Ignore,
+    /// The most recent matching pattern was negated and re-included this path.
+    Whitelist,
+}
+
+/// Error returned when one of the user-supplied patterns fails to compile,
+/// or when expanding an `include:`/`subinclude:` directive fails.
+#[derive(Debug)]
+pub enum PatternError {
+    Glob { pattern: String, source: globset::Error },
+    Regex { pattern: String, source: regex::Error },
+    Io { path: PathBuf, source: std::io::Error },
+    Cycle { path: PathBuf },
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternError::Glob { pattern, source } => {
+                write!(f, "invalid pattern {:?}: {}", pattern, source)
+            }
+            PatternError::Regex { pattern, source } => {
+                write!(f, "invalid regex pattern {:?}: {}", pattern, source)
+            }
+            PatternError::Io { path, source } => {
+                write!(f, "failed to read included pattern file {:?}: {}", path, source)
+            }
+            PatternError::Cycle { path } => {
+                write!(f, "include cycle detected at {:?}", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatternError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PatternError::Glob { source, .. } => Some(source),
+            PatternError::Regex { source, .. } => Some(source),
+            PatternError::Io { source, .. } => Some(source),
+            PatternError::Cycle { .. } => None,
+        }
+    }
+}
+
+/// An ordered list of filter patterns compiled once, so matching a path
+/// against all of them costs one `GlobSet::matches` call (for the
+/// glob/path/rootfilesin patterns) plus one test per `regex:` pattern,
+/// rather than re-parsing every pattern per file.
+pub struct CompiledPatterns {
+    set: GlobSet,
+    /// Maps a `GlobSet` match index back to the rule's original position,
+    /// since regex rules are not added to the set.
+    glob_rule_index: Vec<usize>,
+    /// `(original rule index, compiled regex, subinclude scope)` for every
+    /// `regex:` rule; the scope, if set, is the directory a `subinclude:`d
+    /// regex is restricted to.
+    regexes: Vec<(usize, Regex, Option<PathBuf>)>,
+    /// Negation flag for every rule, indexed by original position.
+    negated: Vec<bool>,
+    /// What a path that no rule matched resolves to. `true` mirrors
+    /// gitignore's default (everything is included unless a rule excludes
+    /// it), matching this module's negation semantics. `false` restores the
+    /// older `--include`-only workflow, where supplying patterns makes them
+    /// an allowlist and anything unmatched is dropped.
+    default_include: bool,
+    /// The scan root every pattern is written relative to (e.g. `docs/**`
+    /// means `<root>/docs/**`). Canonicalized up front, with the
+    /// as-given value kept on failure (e.g. a root that doesn't exist yet
+    /// in a test), so it can be stripped from a canonicalized path later
+    /// without the two disagreeing on symlink resolution.
+    root: PathBuf,
+    /// `.gitignore` rules compiled once per starting directory and reused
+    /// for every other path under it, instead of re-reading and
+    /// re-compiling every `.gitignore` from disk on every call.
+    gitignore_cache: RefCell<HashMap<PathBuf, Rc<CompiledPatterns>>>,
+}
+
+impl CompiledPatterns {
+    /// Compiles an ordered slice of `PatternRule`s into a `CompiledPatterns`.
+    ///
+    /// `default_include` controls what happens to a path no rule matches:
+    /// pass `true` for gitignore-style blocklisting, `false` to keep
+    /// patterns behaving as a strict allowlist. `root` is the scan root
+    /// every pattern is written relative to.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PatternError` if any pattern fails to compile, instead of
+    /// panicking.
+    pub fn compile(
+        patterns: &[PatternRule],
+        default_include: bool,
+        root: impl Into<PathBuf>,
+    ) -> Result<Self, PatternError> {
+        let root = root.into();
+        let root = fs::canonicalize(&root).unwrap_or(root);
+        let mut builder = GlobSetBuilder::new();
+        let mut glob_rule_index = Vec::new();
+        let mut regexes = Vec::new();
+        let mut negated = Vec::with_capacity(patterns.len());
+
+        for (i, rule) in patterns.iter().enumerate() {
+            negated.push(rule.negated);
+            if rule.syntax == PatternSyntax::Regex {
+                let re = Regex::new(&rule.pattern).map_err(|source| PatternError::Regex {
+                    pattern: rule.pattern.clone(),
+                    source,
+                })?;
+                regexes.push((i, re, rule.scope.clone()));
+            } else {
+                let glob_pattern = rule.as_glob_pattern();
+                // `rootfilesin:` must not recurse into subdirectories, so its
+                // `*` can't be allowed to cross a path separator the way
+                // globset's default matcher otherwise lets it.
+                let glob = if rule.syntax == PatternSyntax::RootFilesIn {
+                    GlobBuilder::new(&glob_pattern)
+                        .literal_separator(true)
+                        .build()
+                } else {
+                    Glob::new(&glob_pattern)
+                }
+                .map_err(|source| PatternError::Glob {
+                    pattern: glob_pattern,
+                    source,
+                })?;
+                builder.add(glob);
+                glob_rule_index.push(i);
+            }
+        }
+
+        let set = builder.build().map_err(|source| PatternError::Glob {
+            pattern: "<pattern set>".to_string(),
+            source,
+        })?;
+
+        Ok(CompiledPatterns {
+            set,
+            glob_rule_index,
+            regexes,
+            negated,
+            default_include,
+            root,
+            gitignore_cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// Matches `path_str` against every compiled pattern and applies
+    /// last-match-wins semantics over whichever rule, by original order,
+    /// matched most recently.
+    fn state_for(&self, path_str: &str) -> MatchState {
+        let mut last_match: Option<usize> = None;
+
+        for glob_idx in self.set.matches(path_str) {
+            let rule_idx = self.glob_rule_index[glob_idx];
+            last_match = Some(last_match.map_or(rule_idx, |m| m.max(rule_idx)));
+        }
+
+        for (rule_idx, re, scope) in &self.regexes {
+            let in_scope = scope
+                .as_deref()
+                .is_none_or(|base| Path::new(path_str).starts_with(base));
+            if in_scope && re.is_match(path_str) {
+                last_match = Some(last_match.map_or(*rule_idx, |m| m.max(*rule_idx)));
+            }
+        }
+
+        match last_match {
+            Some(idx) if self.negated[idx] => MatchState::Whitelist,
+            Some(_) => MatchState::Ignore,
+            None => MatchState::None,
+        }
+    }
+
+    /// Checks whether `path` (an absolute path) is excluded by any
+    /// `.gitignore` rule collected from its directory up to the enclosing
+    /// repository root. The compiled rule set for a given starting
+    /// directory is cached here and
+    /// reused for every other path under it, so scanning many files in the
+    /// same directory doesn't re-read and re-parse every `.gitignore` from
+    /// disk for each one.
+    fn is_gitignored(&self, path: &Path) -> bool {
+        let Some(start_dir) = path.parent() else {
+            return false;
+        };
+        let Some(path_str) = path.to_str() else {
+            return false;
+        };
+
+        let compiled = {
+            let mut cache = self.gitignore_cache.borrow_mut();
+            if let Some(existing) = cache.get(start_dir) {
+                Rc::clone(existing)
+            } else {
+                let gitignore_patterns = collect_gitignore_patterns(start_dir);
+                // A malformed `.gitignore` line (e.g. an unterminated `[`)
+                // fails the whole set; this directory then gets no rules.
+                let compiled = CompiledPatterns::compile(&gitignore_patterns, false, PathBuf::new())
+                    .unwrap_or_else(|_| {
+                        CompiledPatterns::compile(&[], false, PathBuf::new()).unwrap()
+                    });
+                let compiled = Rc::new(compiled);
+                cache.insert(start_dir.to_path_buf(), Rc::clone(&compiled));
+                compiled
+            }
+        };
 
-    let excluded = exclude_patterns
-        .iter()
-        .any(|pattern| Pattern::new(pattern).unwrap().matches(path_str));
+        matches!(compiled.state_for(path_str), MatchState::Ignore)
+    }
+}
 
-    // ~~~ Decision ~~~
-    let result = match (included, excluded) {
-        (true, true) => include_priority, // If both include and exclude patterns match, use the include_priority flag
-        (true, false) => true,            // If the path is included and not excluded, include it
-        (false, true) => false,           // If the path is excluded, exclude it
-        (false, false) => include_patterns.is_empty(), // If no include patterns are provided, include everything
+/// How a path is resolved before being matched against patterns and
+/// `.gitignore` rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathResolution {
+    /// Resolve symlinks and make the path absolute via `fs::canonicalize`,
+    /// falling back to `Logical` resolution if that fails (broken symlink,
+    /// case-insensitive mount, file deleted mid-walk).
+    Canonical,
+    /// Lexically normalize the path without touching the filesystem or
+    /// resolving symlinks, so patterns match the path the user actually
+    /// referenced rather than whatever a symlink points at.
+    Logical,
+}
+
+/// Lexically normalizes `path` relative to `root`: joins a relative `path`
+/// onto `root` first (so it shares the same frame of reference a
+/// canonicalized path would), resolves `.` and `..` components without
+/// consulting the filesystem, then strips `root` back off so the result is
+/// relative to the scan root, matching the form patterns like `docs/**` are
+/// written in.
+fn normalize_logical(path: &Path, root: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        root.join(path)
+    };
+
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+
+    normalized
+        .strip_prefix(root)
+        .map(Path::to_path_buf)
+        .unwrap_or(normalized)
+}
+
+/// Determines whether a file should be included based on a pre-compiled,
+/// ordered list of filter patterns.
+///
+/// # Arguments
+///
+/// * `path` - The path to the file to be checked.
+/// * `patterns` - The `CompiledPatterns` built once (via `CompiledPatterns::compile`) for the whole scan.
+/// * `respect_gitignore` - A boolean indicating whether `.gitignore` files should be consulted. Pass `false` to honor a `--no-vcs-ignore` style flag.
+/// * `resolution` - How to resolve `path` before matching; see `PathResolution`.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the file should be included, `false` otherwise.
+pub fn should_include_file(
+    path: &Path,
+    patterns: &CompiledPatterns,
+    respect_gitignore: bool,
+    resolution: PathResolution,
+) -> bool {
+    // ~~~ Resolve path ~~~
+    // `.gitignore` needs a real absolute path to walk its directories from;
+    // pattern matching needs that same path relative to the scan root, since
+    // patterns like `docs/**` are written relative to it.
+    let (absolute_path, relative_path) = match resolution {
+        PathResolution::Canonical => match fs::canonicalize(path) {
+            Ok(canonical) => {
+                let relative = canonical
+                    .strip_prefix(&patterns.root)
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|_| canonical.clone());
+                (canonical, relative)
+            }
+            Err(e) => {
+                error!(
+                    "Failed to canonicalize path {:?} ({}), falling back to logical path",
+                    path, e
+                );
+                let relative = normalize_logical(path, &patterns.root);
+                (patterns.root.join(&relative), relative)
+            }
+        },
+        PathResolution::Logical => {
+            let relative = normalize_logical(path, &patterns.root);
+            (patterns.root.join(&relative), relative)
+        }
+    };
+
+    // ~~~ Gitignore ~~~
+    if respect_gitignore && patterns.is_gitignored(&absolute_path) {
+        debug!("Checking path: {:?}, excluded by .gitignore", absolute_path);
+        return false;
+    }
+
+    // ~~~ Check patterns, last match wins ~~~
+    let path_str = relative_path.to_str().unwrap_or("");
+    let state = patterns.state_for(path_str);
+    let result = match state {
+        MatchState::Whitelist => true,
+        MatchState::Ignore => false,
+        MatchState::None => patterns.default_include,
     };
 
     debug!(
-        "Checking path: {:?}, {}: {}, {}: {}, decision: {}",
+        "Checking path: {:?}, {}: {:?}, decision: {}",
         path_str,
-        "included".bold().green(),
-        included,
-        "excluded".bold().red(),
-        excluded,
+        "match state".bold().green(),
+        state,
         result
     );
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// A fresh, empty directory under the system temp dir, removed when the
+    /// returned guard is dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(label: &str) -> Self {
+            let nonce = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let dir = std::env::temp_dir().join(format!(
+                "code2prompt_filter_test_{}_{}_{}",
+                std::process::id(),
+                label,
+                nonce
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write(dir: &Path, rel: &str, contents: &str) -> PathBuf {
+        let path = dir.join(rel);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn gitignore_excludes_files_nested_under_an_ignored_directory() {
+        let tmp = TempDir::new("gitignore_nested");
+        fs::create_dir_all(tmp.path().join(".git")).unwrap();
+        write(tmp.path(), ".gitignore", "target\n");
+        let nested = write(tmp.path(), "target/deeply/nested/output.bin", "");
+        let sibling = write(tmp.path(), "src/main.rs", "");
+
+        let compiled = CompiledPatterns::compile(&[], true, tmp.path()).unwrap();
+        assert!(compiled.is_gitignored(&nested));
+        assert!(!compiled.is_gitignored(&sibling));
+    }
+
+    #[test]
+    fn gitignore_anchored_pattern_excludes_its_whole_subtree() {
+        let tmp = TempDir::new("gitignore_anchored");
+        fs::create_dir_all(tmp.path().join(".git")).unwrap();
+        write(tmp.path(), ".gitignore", "/build/\n");
+        let nested = write(tmp.path(), "build/debug/output.o", "");
+
+        let compiled = CompiledPatterns::compile(&[], true, tmp.path()).unwrap();
+        assert!(compiled.is_gitignored(&nested));
+    }
+
+    #[test]
+    fn gitignore_negation_re_includes_a_path_excluded_by_an_earlier_rule() {
+        let tmp = TempDir::new("gitignore_negation");
+        fs::create_dir_all(tmp.path().join(".git")).unwrap();
+        write(tmp.path(), ".gitignore", "*.log\n!important.log\n");
+        let dropped = write(tmp.path(), "debug.log", "");
+        let kept = write(tmp.path(), "important.log", "");
+
+        let compiled = CompiledPatterns::compile(&[], true, tmp.path()).unwrap();
+        assert!(compiled.is_gitignored(&dropped));
+        assert!(!compiled.is_gitignored(&kept));
+    }
+
+    #[test]
+    fn gitignore_rules_are_cached_per_directory_instead_of_reread_per_call() {
+        let tmp = TempDir::new("gitignore_cache");
+        fs::create_dir_all(tmp.path().join(".git")).unwrap();
+        write(tmp.path(), ".gitignore", "target\n");
+        let a = write(tmp.path(), "target/a.bin", "");
+        let b = write(tmp.path(), "target/b.bin", "");
+
+        let compiled = CompiledPatterns::compile(&[], true, tmp.path()).unwrap();
+        assert!(compiled.is_gitignored(&a));
+        assert!(compiled.is_gitignored(&b));
+        // Both files share a directory, so the second call should reuse the
+        // first call's compiled rule set rather than adding a second entry.
+        assert_eq!(compiled.gitignore_cache.borrow().len(), 1);
+    }
+
+    #[test]
+    fn pattern_rule_parse_splits_negation_and_syntax_prefix() {
+        let rule = PatternRule::parse("!re:.*\\.rs$");
+        assert!(rule.negated);
+        assert_eq!(rule.syntax, PatternSyntax::Regex);
+        assert_eq!(rule.pattern, ".*\\.rs$");
+
+        let rule = PatternRule::parse("docs/**");
+        assert!(!rule.negated);
+        assert_eq!(rule.syntax, PatternSyntax::Glob);
+    }
+
+    #[test]
+    fn last_match_wins_lets_a_later_negation_re_include_a_path() {
+        let patterns = vec![
+            PatternRule::parse("docs/**"),
+            PatternRule::parse("!docs/api/**"),
+        ];
+        let compiled = CompiledPatterns::compile(&patterns, true, "/").unwrap();
+
+        assert_eq!(compiled.state_for("docs/internal.md"), MatchState::Ignore);
+        assert_eq!(compiled.state_for("docs/api/index.md"), MatchState::Whitelist);
+        assert_eq!(compiled.state_for("src/main.rs"), MatchState::None);
+    }
+
+    #[test]
+    fn should_include_file_matches_patterns_relative_to_the_scan_root() {
+        // The same "exclude everything under docs/ except docs/api/"
+        // example as above, but exercised through `should_include_file`
+        // against real files under a real scan root, rather than bare
+        // hand-typed strings passed straight to `state_for`.
+        let tmp = TempDir::new("scan_root_relative");
+        let internal = write(tmp.path(), "docs/internal.md", "");
+        let api = write(tmp.path(), "docs/api/index.md", "");
+        let source = write(tmp.path(), "src/main.rs", "");
+
+        let patterns = vec![
+            PatternRule::parse("docs/**"),
+            PatternRule::parse("!docs/api/**"),
+        ];
+        let compiled = CompiledPatterns::compile(&patterns, true, tmp.path()).unwrap();
+
+        assert!(!should_include_file(
+            &internal,
+            &compiled,
+            false,
+            PathResolution::Canonical
+        ));
+        assert!(should_include_file(
+            &api,
+            &compiled,
+            false,
+            PathResolution::Canonical
+        ));
+        assert!(should_include_file(
+            &source,
+            &compiled,
+            false,
+            PathResolution::Canonical
+        ));
+    }
+
+    #[test]
+    fn default_include_toggles_the_meaning_of_an_unmatched_path() {
+        let patterns = vec![PatternRule::parse("*.rs")];
+
+        let blocklist = CompiledPatterns::compile(&patterns, true, "/").unwrap();
+        assert_eq!(blocklist.state_for("/repo/README.md"), MatchState::None);
+
+        // Same patterns, but with default_include: false the caller gets
+        // back the old --include-only allowlist behavior at the call site.
+        let allowlist = CompiledPatterns::compile(&patterns, false, "/").unwrap();
+        assert!(!allowlist.default_include);
+    }
+
+    #[test]
+    fn invalid_glob_is_an_error_not_a_panic() {
+        let patterns = vec![PatternRule::parse("[unterminated")];
+        assert!(CompiledPatterns::compile(&patterns, true, "/").is_err());
+    }
+
+    #[test]
+    fn rootfilesin_matches_direct_children_only() {
+        let patterns = vec![PatternRule::parse("rootfilesin:src")];
+        let compiled = CompiledPatterns::compile(&patterns, true, "/").unwrap();
+
+        assert_eq!(compiled.state_for("src/main.rs"), MatchState::Ignore);
+        assert_eq!(compiled.state_for("src/nested/lib.rs"), MatchState::None);
+    }
+
+    #[test]
+    fn path_and_rootfilesin_syntax_match_real_files_relative_to_the_scan_root() {
+        // `path:`/`rootfilesin:` compile to globs anchored to the scan root
+        // (e.g. `build/**`), so they need the same root-relativization as
+        // plain glob patterns to match a real, absolute walked path.
+        let tmp = TempDir::new("path_rootfilesin_real");
+        let built = write(tmp.path(), "build/output.o", "");
+        let nested_src = write(tmp.path(), "src/nested/lib.rs", "");
+        let root_src = write(tmp.path(), "src/main.rs", "");
+
+        let patterns = vec![
+            PatternRule::parse("path:build"),
+            PatternRule::parse("rootfilesin:src"),
+        ];
+        let compiled = CompiledPatterns::compile(&patterns, true, tmp.path()).unwrap();
+
+        assert!(!should_include_file(
+            &built,
+            &compiled,
+            false,
+            PathResolution::Canonical
+        ));
+        assert!(!should_include_file(
+            &root_src,
+            &compiled,
+            false,
+            PathResolution::Canonical
+        ));
+        assert!(should_include_file(
+            &nested_src,
+            &compiled,
+            false,
+            PathResolution::Canonical
+        ));
+    }
+
+    #[test]
+    fn subinclude_scopes_a_regex_rule_to_its_directory() {
+        let tmp = TempDir::new("subinclude_regex");
+        write(tmp.path(), "sub/rules.txt", "re:.*\\.rs$\n");
+
+        let mut patterns = Vec::new();
+        let mut seen = HashSet::new();
+        expand_pattern(
+            "subinclude:sub/rules.txt",
+            Some(tmp.path()),
+            None,
+            &mut seen,
+            &mut patterns,
+        )
+        .unwrap();
+
+        assert_eq!(patterns.len(), 1);
+        assert_eq!(patterns[0].scope.as_deref(), Some(tmp.path().join("sub").as_path()));
+    }
+
+    #[test]
+    fn nested_relative_include_resolves_against_its_own_file_not_the_top_level_caller() {
+        let tmp = TempDir::new("nested_relative_include");
+        write(tmp.path(), "top.txt", "include:mid/mid.txt\n");
+        write(tmp.path(), "mid/mid.txt", "include:leaf.txt\n");
+        write(tmp.path(), "mid/leaf.txt", "*.rs\n");
+
+        let raw = vec![format!("include:{}", tmp.path().join("top.txt").to_string_lossy())];
+        let patterns = PatternRule::parse_all(&raw).unwrap();
+
+        assert_eq!(patterns.len(), 1);
+        assert_eq!(patterns[0].pattern, "*.rs");
+    }
+
+    #[test]
+    fn include_cycle_is_reported_instead_of_overflowing_the_stack() {
+        let tmp = TempDir::new("include_cycle");
+        write(tmp.path(), "a.txt", "include:b.txt\n");
+        write(tmp.path(), "b.txt", "include:a.txt\n");
+
+        let raw = vec![format!("include:{}", tmp.path().join("a.txt").to_string_lossy())];
+        let result = PatternRule::parse_all(&raw);
+
+        assert!(matches!(result, Err(PatternError::Cycle { .. })));
+    }
+
+    #[test]
+    fn normalize_logical_resolves_dot_segments_and_relativizes_to_the_scan_root() {
+        let normalized = normalize_logical(Path::new("/a/b/../c/./d"), Path::new("/a"));
+        assert_eq!(normalized, PathBuf::from("c/d"));
+    }
+
+    #[test]
+    fn logical_resolution_matches_patterns_against_a_relative_path_input() {
+        // A caller passing a path already relative to the scan root (the
+        // common case for `PathResolution::Logical`, which deliberately
+        // avoids touching the filesystem) should match the same as an
+        // absolute one.
+        let tmp = TempDir::new("logical_relative_input");
+        write(tmp.path(), "docs/internal.md", "");
+
+        let patterns = vec![PatternRule::parse("docs/**")];
+        let compiled = CompiledPatterns::compile(&patterns, true, tmp.path()).unwrap();
+
+        assert!(!should_include_file(
+            Path::new("docs/internal.md"),
+            &compiled,
+            false,
+            PathResolution::Logical
+        ));
+    }
+
+    #[test]
+    fn canonicalize_failure_falls_back_to_logical_path_instead_of_excluding() {
+        // A pattern that doesn't match the path below, so the only way this
+        // returns `true` is by falling through to `default_include` instead
+        // of taking the old "canonicalize failed -> exclude" early return.
+        let patterns = vec![PatternRule::parse("*.txt")];
+        let compiled = CompiledPatterns::compile(&patterns, true, "/").unwrap();
+        let missing = Path::new("/definitely/does/not/exist/on/this/machine/file.rs");
+
+        assert!(should_include_file(
+            missing,
+            &compiled,
+            false,
+            PathResolution::Canonical
+        ));
+    }
+}